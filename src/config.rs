@@ -0,0 +1,74 @@
+//! Aspect-ratio bucket configuration.
+//!
+//! By default images are sorted into the built-in tall/square/wide split, but an optional
+//! TOML file (see `--config`) can replace those with any named ranges the user wants.
+
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+
+/// Margin either side of a 1:1 ratio that still counts as `sqr` in the built-in buckets.
+const SQUARE_EPSILON: f32 = 1e-6;
+
+/// A named aspect-ratio bucket. An image is classified under `name` when its
+/// `width as f32 / height as f32` ratio falls in `[min_ratio, max_ratio)`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Bucket {
+    pub name: String,
+    pub min_ratio: f32,
+    pub max_ratio: f32,
+}
+
+/// User-configurable aspect-ratio buckets, loaded from TOML.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    pub buckets: Vec<Bucket>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config { buckets: default_buckets() }
+    }
+}
+
+/// The built-in tall/square/wide split, used when no config file is supplied.
+pub fn default_buckets() -> Vec<Bucket> {
+    vec![
+        Bucket { name: "tall".to_string(), min_ratio: f32::NEG_INFINITY,  max_ratio: 1.0 - SQUARE_EPSILON },
+        Bucket { name: "sqr".to_string(),  min_ratio: 1.0 - SQUARE_EPSILON, max_ratio: 1.0 + SQUARE_EPSILON },
+        Bucket { name: "wide".to_string(), min_ratio: 1.0 + SQUARE_EPSILON, max_ratio: f32::INFINITY },
+    ]
+}
+
+/// Load bucket configuration from a TOML file at `path`, falling back to the built-in
+/// tall/square/wide split when no path is given.
+pub fn load(path: Option<&Path>) -> Result<Config, failure::Error> {
+    match path {
+        Some(path) => {
+            let contents = fs::read_to_string(path)?;
+            Ok(toml::from_str(&contents)?)
+        },
+        None => Ok(Config::default()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_buckets_classify_as_before() {
+        let buckets = default_buckets();
+        let ratio_of = |x: u32, y: u32| x as f32 / y as f32;
+        let classify = |ratio: f32| buckets.iter().find(|b| ratio >= b.min_ratio && ratio < b.max_ratio).map(|b| b.name.as_str());
+        assert_eq!(classify(ratio_of(2, 3)), Some("tall"));
+        assert_eq!(classify(ratio_of(3, 2)), Some("wide"));
+        assert_eq!(classify(ratio_of(2, 2)), Some("sqr"));
+    }
+
+    #[test]
+    fn test_load_without_path_returns_default() {
+        let config = load(None).unwrap();
+        assert_eq!(config.buckets.len(), default_buckets().len());
+    }
+}
@@ -2,28 +2,27 @@
 #[macro_use] extern crate log;
 extern crate stderrlog;
 
+mod config;
+
 use aho_corasick::{AhoCorasick, AhoCorasickBuilder};
 use arraystring::{ArrayString, typenum::U4};
+use config::{Bucket, Config};
+use exif::{In, Tag};
+use ignore::WalkBuilder;
 use image::image_dimensions;
-use std::cmp::Ordering;
-use std::fs::{create_dir_all, rename};
+use indicatif::{ProgressBar, ProgressDrawTarget};
+use rayon::prelude::*;
+use std::collections::HashSet;
+use std::fs::{self, create_dir_all, rename, File};
+use std::io::BufReader;
+use std::mem::swap;
 use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU32, Ordering as AtomicOrdering};
 use structopt::StructOpt;
-use walkdir::WalkDir;
 
 type FourChar = ArrayString<U4>;
 
-enum Orientation { Tall, Wide, Square }
-impl Orientation {
-    fn to_arrstr(&self) -> FourChar {
-        match self {
-            Orientation::Tall =>   unsafe {FourChar::from_str_unchecked("tall")},
-            Orientation::Wide =>   unsafe {FourChar::from_str_unchecked("wide")},
-            Orientation::Square => unsafe {FourChar::from_str_unchecked("sqr")},
-        }
-    }
-}
-
 lazy_static! {
     static ref AC: AhoCorasick = unsafe {
         AhoCorasickBuilder::new()
@@ -59,18 +58,46 @@ struct Opt {
     quiet: bool,
     #[structopt(long, help = "Overrwite files in the destination directory if file names are the same. Without this flag set, the default behavior is to append a number to make the filename unique.")]
     overwrite: bool,
+    #[structopt(long, parse(from_os_str), help = "Path to a TOML file defining named aspect-ratio buckets. Without this flag, images are sorted into the built-in tall/sqr/wide buckets.")]
+    config: Option<PathBuf>,
+    #[structopt(long, conflicts_with = "symlink", help = "Copy files into the orientation directory instead of moving them, leaving the originals in place.")]
+    copy: bool,
+    #[structopt(long, conflicts_with = "copy", help = "Create a symlink in the orientation directory pointing at the original file instead of moving it.")]
+    symlink: bool,
+    #[structopt(long, help = "Include hidden files and directories (dotfiles). Off by default, same as ripgrep.")]
+    hidden: bool,
+    #[structopt(long, help = "Do not respect .gitignore, .ignore, or other ignore files when walking the input directory.")]
+    no_ignore: bool,
+    #[structopt(long, help = "Classify by raw stored-pixel dimensions, ignoring the EXIF Orientation tag on JPEG/TIFF images.")]
+    ignore_exif: bool,
+    #[structopt(long, help = "Print the planned src -> dst mapping without moving, copying, or symlinking anything.")]
+    dry_run: bool,
 }
 
 fn main() -> std::io::Result<()> {
     let opts: Opt = init();
-    if !opts.rename {
-        create_orientation_dirs(&opts)?;
+    let config: Config = match config::load(opts.config.as_deref()) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("Failed to load config file {:?}: {:?}", opts.config, e);
+            std::process::exit(1);
+        }
+    };
+    if !opts.rename && !opts.dry_run {
+        create_orientation_dirs(&opts, &config.buckets)?;
     } else {
         drop(&opts.output_dir);
     };
     let images = image_paths(&opts);
-    let dests = get_dsts(&opts, &images);
-    let moved: u32 = mv_files(&images, dests, &opts);
+    // Shared by every `make_uniq` call in `mv_files`/`print_dry_run` so it can never
+    // hand two concurrent workers the same "unique" name.
+    let reserved: Mutex<HashSet<PathBuf>> = Mutex::new(HashSet::new());
+    let dests = get_dsts(&opts, &images, &config.buckets);
+    if opts.dry_run {
+        print_dry_run(&images, dests, &opts, &reserved);
+        return Ok(());
+    }
+    let moved: u32 = mv_files(&images, dests, &opts, &reserved);
     if !opts.quiet {
         println!("Processed {} files successfully.", moved);
     }
@@ -90,63 +117,70 @@ fn init() -> Opt {
     return opt
 }
 
-/// Create directories to place each orientation of image into.
-fn create_orientation_dirs(opts: &Opt) -> std::io::Result<()> {
+/// Create a directory for each configured bucket to place images of that orientation into.
+fn create_orientation_dirs(opts: &Opt, buckets: &[Bucket]) -> std::io::Result<()> {
     // TODO: Instead of panicking when output dirs cannot be written, prompt user
     //       asking if they would like to rename in-place instead. Output error messages,
     //       and kindly suggest to the user to chown dirs with permission errors.
     let outstr = opts.output_dir.to_str().unwrap_or("");
-    create_dir_all(format!("{}/{}", outstr, Orientation::Tall.to_arrstr()))?;
-    create_dir_all(format!("{}/{}", outstr, Orientation::Wide.to_arrstr()))?;
-    create_dir_all(format!("{}/{}", outstr, Orientation::Square.to_arrstr()))?;
+    for bucket in buckets {
+        create_dir_all(format!("{}/{}", outstr, bucket.name))?;
+    }
     Ok(())
 }
 
 /// Walk the input directory, possibly recursively, and return paths of image files.
+///
+/// Uses the `ignore` crate's walker (the one behind ripgrep) so `.gitignore`, `.ignore`,
+/// and dotfiles are skipped by default, matching ripgrep's own `--hidden`/`--no-ignore` flags.
+/// If the supplied input path is a file, it is yielded alone.
 fn image_paths(opts: &Opt) -> Vec<PathBuf> {
-    let max_depth: usize = match opts.recursive {
-        true => 255,
-        false => 1,
+    let max_depth: Option<usize> = match opts.recursive {
+        true => None,
+        false => Some(1),
     };
-    // If the supplied input path is a file, operate on it alone.
-    let min_depth: usize = match opts.input_dir.is_dir() {
-        true => 1,
-        false => 0,
-    };
-    WalkDir::new(&opts.input_dir)
-        .min_depth(min_depth)
+    WalkBuilder::new(&opts.input_dir)
         .max_depth(max_depth)
-        .into_iter()
+        .hidden(!opts.hidden)
+        .ignore(!opts.no_ignore)
+        .git_ignore(!opts.no_ignore)
+        .git_global(!opts.no_ignore)
+        .git_exclude(!opts.no_ignore)
+        .build()
         .filter_map( |dir| dir.ok() )
-        .filter( |dir| dir.file_type().is_file() && has_image_extension(dir.path()) )
+        .filter( |dir| dir.file_type().map_or(false, |ft| ft.is_file()) && has_image_extension(dir.path()) )
         .map( |dir| dir.into_path() )
         .collect()
 }
 
 /// Given a set of image paths, find where they should be moved to (including in-place renaming).
+/// Runs across a rayon work-stealing pool since `image_orientation` is I/O-bound per file.
 #[inline]
-fn get_dsts(opts: &Opt, imgs: &Vec<PathBuf>) -> Vec<Option<PathBuf>> {
-    imgs.iter()
-        .map(|img| dst_path(opts, img))
+fn get_dsts(opts: &Opt, imgs: &Vec<PathBuf>, buckets: &[Bucket]) -> Vec<Option<PathBuf>> {
+    imgs.par_iter()
+        .map(|img| dst_path(opts, img, buckets))
         .collect()
 }
 
 /// Find destination path based on image orientation.
+///
+/// The returned path is not yet guaranteed unique - `mv_files`/`print_dry_run` own that,
+/// via `make_uniq`, so uniqueness is resolved in exactly one place.
 #[inline]
-fn dst_path(opts: &Opt, img_path: &Path) -> Option<PathBuf> {
+fn dst_path(opts: &Opt, img_path: &Path, buckets: &[Bucket]) -> Option<PathBuf> {
     let imgfile = img_path.file_name().unwrap();
-    let ori: Orientation = match image_orientation(img_path) {
-        Some(ori) => ori,
+    let bucket: &str = match image_orientation(img_path, buckets, opts.ignore_exif) {
+        Some(bucket) => bucket,
         None => return None
     };
     match opts.rename {
-        true => match prepend_orientation(img_path) {
+        true => match prepend_orientation(img_path, buckets, opts.ignore_exif) {
             Some(renamed) => Some(renamed),
             None => return None
         },
         false => {
             let mut out = opts.output_dir.to_owned();
-            out.push(ori.to_arrstr().as_str());
+            out.push(bucket);
             out.push(imgfile);
             if out.as_path() == img_path {
                 drop(out);
@@ -158,64 +192,151 @@ fn dst_path(opts: &Opt, img_path: &Path) -> Option<PathBuf> {
     }
 }
 
-/// Iterate source and destination path vectors, moving matching indexes.
+/// Iterate source and destination path vectors, moving matching indexes in parallel.
 // TODO: Break these long filters/maps into functions.
-fn mv_files(src_paths: &Vec<PathBuf>, dst_paths: Vec<Option<PathBuf>>, opts: &Opt) -> u32 {
+fn mv_files(src_paths: &Vec<PathBuf>, dst_paths: Vec<Option<PathBuf>>, opts: &Opt, reserved: &Mutex<HashSet<PathBuf>>) -> u32 {
     if src_paths.len() != dst_paths.len() {
         panic!("Source files do not match calculated destination files.\nSource files: {:?}\nDestinations: {:?}", src_paths, dst_paths);
     }
+    let moved = AtomicU32::new(0);
+    let pb = ProgressBar::new(src_paths.len() as u64);
+    if opts.quiet {
+        pb.set_draw_target(ProgressDrawTarget::hidden());
+    }
     src_paths
-        .iter()
-        .zip(dst_paths.iter())
-        .filter( |sd| !sd.1.is_none() )
-        .filter_map( |sd| {
-            let dst = sd.1.to_owned().unwrap();
-            if !opts.overwrite {
-                if dst.exists() {
-                    Some( (sd.0, make_uniq(dst)) )
-                } else {
-                    Some( (sd.0, dst) )
-                }
-            } else {
-                Some( (sd.0, dst) )
-            }
-        })
-        .map( |sd| {
-            match rename(&sd.0, &sd.1) {
+        .par_iter()
+        .zip(dst_paths.par_iter())
+        .filter_map( |sd| sd.1.to_owned().map(|dst| (sd.0, dst)) )
+        .for_each( |(src, dst)| {
+            let dst = if !opts.overwrite { make_uniq(dst, reserved) } else { dst };
+            match transfer_file(src, &dst, opts) {
                 Ok(_) => {
-                    debug!("Moved {:?} to {:?}", &sd.0, &sd.1);
-                    1
+                    debug!("Moved {:?} to {:?}", src, &dst);
+                    moved.fetch_add(1, AtomicOrdering::Relaxed);
                 },
                 Err(e) => {
-                    error!("Failed to move\n  {:?}\nto\n  {:?}\nError: {:?}.", sd.0, sd.1, e);
-                    0
+                    error!("Failed to move\n  {:?}\nto\n  {:?}\nError: {:?}.", src, dst, e);
                 }
             }
-        })
-        .fold(0, |acc, ret| acc + ret)
+            pb.inc(1);
+        });
+    pb.finish_and_clear();
+    moved.load(AtomicOrdering::Relaxed)
+}
+
+/// Print the `src -> dst` mapping `mv_files` would perform, without moving anything.
+///
+/// Resolves unique names through the same `reserved` set so collisions that `make_uniq`
+/// would hit during a real run are visible in the plan.
+fn print_dry_run(src_paths: &Vec<PathBuf>, dst_paths: Vec<Option<PathBuf>>, opts: &Opt, reserved: &Mutex<HashSet<PathBuf>>) {
+    for (src, dst) in src_paths.iter().zip(dst_paths.into_iter()) {
+        if let Some(dst) = dst {
+            let dst = if !opts.overwrite { make_uniq(dst, reserved) } else { dst };
+            println!("{} -> {}", src.display(), dst.display());
+        }
+    }
+}
+
+/// Raw OS error number for EXDEV ("Invalid cross-device link"), the errno `rename` returns
+/// when source and destination are on different filesystems.
+#[cfg(unix)]
+const EXDEV: i32 = libc::EXDEV;
+/// Windows' analogous Win32 error code (`ERROR_NOT_SAME_DEVICE`).
+#[cfg(windows)]
+const EXDEV: i32 = 17;
+
+/// Transfer `src` to `dst` according to `opts`: plain move (the default), copy (`--copy`,
+/// leaving the original in place), or symlink (`--symlink`, pointing at the original).
+///
+/// A plain move that hits EXDEV (crossing filesystems) falls back to a copy followed by
+/// removing the source, since `rename` cannot work across devices.
+fn transfer_file(src: &Path, dst: &Path, opts: &Opt) -> std::io::Result<()> {
+    if opts.symlink {
+        // `src` is typically relative to the input dir; canonicalize so the link still
+        // resolves once it's sitting in a different (and possibly deeper) output directory.
+        let target = fs::canonicalize(src)?;
+        return symlink(&target, dst);
+    }
+    if opts.copy {
+        fs::copy(src, dst)?;
+        return Ok(());
+    }
+    match rename(src, dst) {
+        Ok(_) => Ok(()),
+        Err(e) if e.raw_os_error() == Some(EXDEV) => {
+            debug!("{:?} -> {:?} crosses filesystems, falling back to copy+remove.", src, dst);
+            fs::copy(src, dst)?;
+            fs::remove_file(src)
+        },
+        Err(e) => Err(e),
+    }
 }
 
-/// Determine the orientation of an image.
+#[cfg(unix)]
+fn symlink(src: &Path, dst: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(src, dst)
+}
+
+#[cfg(windows)]
+fn symlink(src: &Path, dst: &Path) -> std::io::Result<()> {
+    std::os::windows::fs::symlink_file(src, dst)
+}
+
+/// Determine which configured bucket an image's aspect ratio falls into.
+///
+/// Unless `ignore_exif` is set, a JPEG/TIFF's EXIF `Orientation` tag is consulted first:
+/// values 5-8 mean the stored pixels are rotated 90 degrees relative to how the image
+/// displays, so width and height are swapped before the ratio is computed.
 #[inline]
-fn image_orientation(img_path: &Path) -> Option<Orientation> {
-    let (x, y): (u32, u32) = match image_dimensions(img_path) {
+fn image_orientation<'a>(img_path: &Path, buckets: &'a [Bucket], ignore_exif: bool) -> Option<&'a str> {
+    let (mut x, mut y): (u32, u32) = match image_dimensions(img_path) {
         Ok(xy) => {xy},
         Err(e) => {
             warn!("Error finding orientation of image: {:?}. Image will not be moved or renamed. Error: {:?}", img_path, e);
             return None
         }
     };
-    match x.cmp(&y) {
-        Ordering::Less    => { Some(Orientation::Tall) },
-        Ordering::Greater => { Some(Orientation::Wide) },
-        Ordering::Equal   => { Some(Orientation::Square) },
+    if !ignore_exif && supports_exif(img_path) {
+        if let Some(orientation) = exif_orientation(img_path) {
+            if (5..=8).contains(&orientation) {
+                swap(&mut x, &mut y);
+            }
+        }
+    }
+    let ratio = x as f32 / y as f32;
+    buckets.iter()
+        .find(|bucket| ratio >= bucket.min_ratio && ratio < bucket.max_ratio)
+        .map(|bucket| bucket.name.as_str())
+}
+
+/// Return true if `path`'s extension is a format the `exif` crate knows how to read.
+#[inline]
+fn supports_exif(path: &Path) -> bool {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some(ext) => ext.eq_ignore_ascii_case("jpg")
+            || ext.eq_ignore_ascii_case("jpeg")
+            || ext.eq_ignore_ascii_case("tif")
+            || ext.eq_ignore_ascii_case("tiff"),
+        None => false,
     }
 }
 
+/// Read the EXIF `Orientation` tag from an image, if present.
+fn exif_orientation(path: &Path) -> Option<u32> {
+    let file = File::open(path).ok()?;
+    let mut reader = BufReader::new(file);
+    let exif = exif::Reader::new().read_from_container(&mut reader).ok()?;
+    exif.get_field(Tag::Orientation, In::PRIMARY)?.value.get_uint(0)
+}
+
 /// Prepend the image orientation to its filename.
-fn prepend_orientation(p: &Path) -> Option<PathBuf> {
-    let ori: Orientation = match image_orientation(p) {
-        Some(ori) => ori,
+///
+/// Does not call `make_uniq` itself - the returned path still needs to be checked for
+/// collisions, which `mv_files`/`print_dry_run` do once, downstream, for every path
+/// regardless of which branch of `dst_path` produced it (unless `--overwrite` is set).
+fn prepend_orientation(p: &Path, buckets: &[Bucket], ignore_exif: bool) -> Option<PathBuf> {
+    let bucket: &str = match image_orientation(p, buckets, ignore_exif) {
+        Some(bucket) => bucket,
         None => return None
     };
 
@@ -223,13 +344,10 @@ fn prepend_orientation(p: &Path) -> Option<PathBuf> {
     new_name.set_file_name(
         format!(
             "{}_{}",
-            ori.to_arrstr().as_str(),
+            bucket,
             p.file_name().unwrap().to_str().unwrap()));
 
     if new_name.as_path() != p {
-        if new_name.exists() {
-            new_name = make_uniq(new_name);
-        }
         trace!("Renamed {:?} to {:?}", p, new_name);
         Some(new_name)
     } else {
@@ -239,13 +357,27 @@ fn prepend_orientation(p: &Path) -> Option<PathBuf> {
 }
 
 /// Try to make a filename unique by appending an integer to the end of a filename.
+///
+/// `reserved` guards against the time-of-check/time-of-use race that concurrent movers would
+/// otherwise hit: a candidate is only handed out once it is inserted into `reserved`, so two
+/// threads can never walk away with the same "unique" destination. The `exists()` filesystem
+/// probe itself happens outside the lock so it doesn't serialize the whole parallel move phase;
+/// the lock is only taken to settle an actual candidate, with a re-check inside it to close the
+/// gap between the probe and the reservation.
 // TODO: Do this smarter and/or allow user to configure alternative suffix (timestamp? uuid?)
 #[inline]
 #[cold]
-fn make_uniq(fpath: PathBuf) -> PathBuf {
+fn make_uniq(fpath: PathBuf, reserved: &Mutex<HashSet<PathBuf>>) -> PathBuf {
     let mut i: u16 = 0;
-    let mut new_name: PathBuf = fpath.to_owned();
-    while new_name.exists() {
+    let mut new_name: PathBuf = fpath;
+    loop {
+        if !new_name.exists() {
+            let mut guard = reserved.lock().unwrap();
+            if !guard.contains(&new_name) {
+                guard.insert(new_name.clone());
+                return new_name;
+            }
+        }
         i += 1;
         new_name.set_file_name(
             format!("{}_{}.{}",
@@ -253,9 +385,6 @@ fn make_uniq(fpath: PathBuf) -> PathBuf {
                 i,
                 new_name.extension().unwrap().to_str().unwrap()));
     }
-    drop(i);
-    trace!("Renamed file to: {:?}", fpath);
-    new_name
 }
 
 /// Return true if the given path has an image file extension.
@@ -282,6 +411,7 @@ mod tests {
     // use std::panic;
     use image::RgbImage;
     use std::sync::Once;
+    use walkdir::WalkDir;
 
     static INIT: Once = Once::new();
 
@@ -294,6 +424,13 @@ mod tests {
             verbose:    5,
             quiet:      false,
             overwrite:  false,
+            config:     None,
+            copy:       false,
+            symlink:    false,
+            hidden:     false,
+            no_ignore:  false,
+            ignore_exif: false,
+            dry_run:    false,
         }
     }
 
@@ -362,7 +499,7 @@ mod tests {
     fn test_create_orientation_dirs() {
         init();
         let opts = test_opts();
-        let ret = create_orientation_dirs(&opts);
+        let ret = create_orientation_dirs(&opts, &config::default_buckets());
         assert_eq!(ret.is_ok(), true);
         let mut wts: (u8, u8, u8) = (0, 0, 0);
         for dir in WalkDir::new(&opts.output_dir).min_depth(0).max_depth(5).into_iter().filter_map(|e| e.ok()) {
@@ -410,9 +547,10 @@ mod tests {
         opts.input_dir = root.path().to_owned();
 
         // Non-recursive walk. Expect 3 images.
+        let buckets = config::default_buckets();
         let src_paths = image_paths(&opts);
         assert_eq!(src_paths.len(), 3);
-        let dst_paths = get_dsts(&opts, &src_paths);
+        let dst_paths = get_dsts(&opts, &src_paths, &buckets);
         assert_eq!(dst_paths.len(), 3);
         drop(src_paths);
         drop(dst_paths);
@@ -421,7 +559,7 @@ mod tests {
         opts.recursive = true;
         let src_paths = image_paths(&opts);
         assert_eq!(src_paths.len(), 15);
-        let dst_paths = get_dsts(&opts, &src_paths);
+        let dst_paths = get_dsts(&opts, &src_paths, &buckets);
         assert_eq!(dst_paths.len(), 15);
         drop(src_paths);
         drop(dst_paths);
@@ -431,7 +569,7 @@ mod tests {
         opts.overwrite = true;
         let src_paths = image_paths(&opts);
         assert_eq!(src_paths.len(), 3);
-        let dst_paths = get_dsts(&opts, &src_paths);
+        let dst_paths = get_dsts(&opts, &src_paths, &buckets);
         assert_eq!(dst_paths.len(), 3);
         drop(src_paths);
         drop(dst_paths);